@@ -2,50 +2,209 @@ use std::hash::Hasher;
 use std::collections::BinaryHeap;
 use std::io::Read;
 use std::io::Write;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::Ordering;
 
 use crossbeam_channel::bounded;
+use crossbeam_epoch::{Atomic, Owned};
 use crossbeam_utils::thread;
 use num_cpus;
+use sha2::Digest as _;
 use siphasher::sip128::{Hasher128, SipHasher};
 
-struct HashWriter<T> {
-    hash: T,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Algorithm {
+    #[default]
+    Sip128,
+    Blake3,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sip128 => "SIP128",
+            Algorithm::Blake3 => "BLAKE3",
+            Algorithm::Sha256 => "SHA256",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "sip128" | "sip" => Some(Algorithm::Sip128),
+            "blake3" => Some(Algorithm::Blake3),
+            "sha256" | "sha2" => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+const MAX_DIGEST_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Digest {
+    bytes: [u8; MAX_DIGEST_LEN],
+    len: u8,
+}
+
+impl Default for Digest {
+    fn default() -> Self {
+        Digest {
+            bytes: [0; MAX_DIGEST_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl Digest {
+    fn from_bytes(src: &[u8]) -> Self {
+        assert!(src.len() <= MAX_DIGEST_LEN, "digest too wide to store inline");
+        let mut bytes = [0u8; MAX_DIGEST_LEN];
+        bytes[..src.len()].copy_from_slice(src);
+        Digest {
+            bytes,
+            len: src.len() as u8,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl std::fmt::LowerHex for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.as_slice() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+enum BlockHasher {
+    Sip128(SipHasher),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Box<sha2::Sha256>),
+}
+
+impl BlockHasher {
+    fn empty(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sip128 => BlockHasher::Sip128(SipHasher::new()),
+            Algorithm::Blake3 => BlockHasher::Blake3(Box::new(blake3::Hasher::new())),
+            Algorithm::Sha256 => BlockHasher::Sha256(Box::new(sha2::Sha256::new())),
+        }
+    }
+
+    fn new_leaf(algorithm: Algorithm, index: u64) -> Self {
+        let mut hasher = Self::empty(algorithm);
+        hasher.write(&index.to_le_bytes());
+        hasher
+    }
+
+    fn new_node(algorithm: Algorithm, level: u32) -> Self {
+        let mut hasher = Self::empty(algorithm);
+        hasher.write(&[level as u8]);
+        hasher
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            BlockHasher::Sip128(h) => Hasher::write(h, bytes),
+            BlockHasher::Blake3(h) => {
+                h.update(bytes);
+            }
+            BlockHasher::Sha256(h) => h.update(bytes),
+        }
+    }
+
+    fn finish(self) -> Digest {
+        match self {
+            BlockHasher::Sip128(h) => {
+                let hash: u128 = h.finish128().into();
+                Digest::from_bytes(&hash.to_be_bytes())
+            }
+            BlockHasher::Blake3(h) => Digest::from_bytes(h.finalize().as_bytes()),
+            BlockHasher::Sha256(h) => Digest::from_bytes(&sha2::Digest::finalize(*h)),
+        }
+    }
+}
+
+struct HashWriter {
+    hash: BlockHasher,
     len: u64,
 }
 
 #[derive(Debug, Default)]
 struct HashResult {
-    hash: u128,
+    hash: Digest,
     len: u64,
 }
 
-impl HashResult {
-    fn add(&mut self, other: HashResult) {
-        self.hash ^= other.hash;
-        self.len += other.len;
+struct MerkleTree {
+    algorithm: Algorithm,
+    stack: Vec<(u32, Digest)>,
+}
+
+impl MerkleTree {
+    fn new(algorithm: Algorithm) -> Self {
+        Self {
+            algorithm,
+            stack: Vec::new(),
+        }
+    }
+
+    fn combine(algorithm: Algorithm, level: u32, left: &Digest, right: &Digest) -> Digest {
+        let mut hasher = BlockHasher::new_node(algorithm, level);
+        hasher.write(left.as_slice());
+        hasher.write(right.as_slice());
+        hasher.finish()
+    }
+
+    fn push_leaf(&mut self, leaf: Digest) {
+        let mut level = 0u32;
+        let mut node = leaf;
+
+        while let Some(&(top_level, top_hash)) = self.stack.last() {
+            if top_level != level {
+                break;
+            }
+            self.stack.pop();
+            node = Self::combine(self.algorithm, top_level, &top_hash, &node);
+            level += 1;
+        }
+
+        self.stack.push((level, node));
+    }
+
+    fn finish(mut self) -> Digest {
+        let (_, mut acc) = match self.stack.pop() {
+            Some(entry) => entry,
+            None => return BlockHasher::empty(self.algorithm).finish(),
+        };
+
+        while let Some((level, left)) = self.stack.pop() {
+            acc = Self::combine(self.algorithm, level, &left, &acc);
+        }
+
+        acc
     }
 }
 
-impl<T> HashWriter<T>
-where
-    T: Hasher128,
-{
-    fn new(hash: T) -> Self {
+impl HashWriter {
+    fn new(hash: BlockHasher) -> Self {
         Self { hash, len: 0 }
     }
 
     fn close(self) -> HashResult {
         HashResult {
-            hash: self.hash.finish128().into(),
+            hash: self.hash.finish(),
             len: self.len,
         }
     }
 }
 
-impl<T> Write for HashWriter<T>
-where
-    T: std::hash::Hasher,
-{
+impl Write for HashWriter {
     fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
         self.len += bytes.len() as u64;
         self.hash.write(bytes);
@@ -57,9 +216,92 @@ where
     }
 }
 
-struct Buf(u64, Vec<u8>);
+enum BufData<'a> {
+    Owned(Vec<u8>),
+    Borrowed(&'a [u8]),
+}
+
+impl<'a> BufData<'a> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            BufData::Owned(buf) => &buf[..],
+            BufData::Borrowed(slice) => slice,
+        }
+    }
+}
+
+struct Buf<'a>(u64, BufData<'a>);
 struct HashResultIdx(u64, HashResult);
 
+struct PoolNode {
+    buf: ManuallyDrop<Vec<u8>>,
+    next: Atomic<PoolNode>,
+}
+
+struct BufferPool {
+    head: Atomic<PoolNode>,
+}
+
+impl BufferPool {
+    fn new(capacity: usize, buf_size: usize) -> Self {
+        let pool = BufferPool {
+            head: Atomic::null(),
+        };
+        for _ in 0..capacity {
+            pool.release(Vec::with_capacity(buf_size));
+        }
+        pool
+    }
+
+    fn acquire(&self) -> Vec<u8> {
+        let guard = &crossbeam_epoch::pin();
+        loop {
+            let head = self.head.load(Ordering::Acquire, guard);
+            let head_ref = match unsafe { head.as_ref() } {
+                Some(node) => node,
+                None => {
+                    std::hint::spin_loop();
+                    continue;
+                }
+            };
+
+            let next = head_ref.next.load(Ordering::Acquire, guard);
+            if self
+                .head
+                .compare_exchange_weak(head, next, Ordering::AcqRel, Ordering::Relaxed, guard)
+                .is_ok()
+            {
+                let buf = unsafe { std::ptr::read(&*head_ref.buf) };
+                unsafe { guard.defer_destroy(head) };
+                return buf;
+            }
+        }
+    }
+
+    fn release(&self, buf: Vec<u8>) {
+        let guard = &crossbeam_epoch::pin();
+        let mut new_head = Owned::new(PoolNode {
+            buf: ManuallyDrop::new(buf),
+            next: Atomic::null(),
+        });
+
+        loop {
+            let head = self.head.load(Ordering::Acquire, guard);
+            new_head.next.store(head, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+                guard,
+            ) {
+                Ok(_) => break,
+                Err(e) => new_head = e.new,
+            }
+        }
+    }
+}
+
 impl PartialEq for HashResultIdx {
     fn eq(&self, o: &Self) -> bool {
         o.0.eq(&self.0)
@@ -79,28 +321,74 @@ impl Ord for HashResultIdx {
 
 const BUF_SIZE: usize = 1024 * 512;
 
-fn main() {
-    let mut exitcode = 0;
-    let workers = num_cpus::get();
+struct Options {
+    algorithm: Algorithm,
+    manifest: bool,
+    paths: Vec<String>,
+}
+
+fn parse_options() -> Options {
+    let mut algorithm = None;
+    let mut manifest = false;
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-a" | "--algorithm" => {
+                let value = args.next().unwrap_or_else(|| {
+                    eprintln!("{} requires a value", arg);
+                    std::process::exit(2);
+                });
+                algorithm = Some(Algorithm::parse(&value).unwrap_or_else(|| {
+                    eprintln!("unknown algorithm: {}", value);
+                    std::process::exit(2);
+                }));
+            }
+            "--manifest" => manifest = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    let algorithm = algorithm
+        .or_else(|| {
+            std::env::var("QUICKHASH_ALGORITHM")
+                .ok()
+                .and_then(|value| Algorithm::parse(&value))
+        })
+        .unwrap_or_default();
 
-    let (full_buf_tx, full_buf_rx) = bounded::<Buf>(workers * 2);
-    let (empty_buf_tx, empty_buf_rx) = bounded::<Buf>(workers * 2);
+    Options {
+        algorithm,
+        manifest,
+        paths,
+    }
+}
+
+type BlockObserver = Box<dyn Fn(u64, u64, u64, &Digest) + Send + Sync>;
+
+fn hash_pipeline<'env>(
+    workers: usize,
+    algorithm: Algorithm,
+    observer: Option<&'env BlockObserver>,
+    pool: Option<&'env BufferPool>,
+    feed: impl FnOnce(&crossbeam_channel::Sender<Buf<'env>>) -> i32 + 'env,
+) -> (HashResult, i32) {
+    let (full_buf_tx, full_buf_rx) = bounded::<Buf<'env>>(workers * 2);
     let (results_tx, results_rx) = bounded::<HashResultIdx>(workers * 2);
     let (finish_tx, finish_rx) = bounded::<HashResult>(1);
 
-    thread::scope(|s| {
+    let exitcode = thread::scope(|s| {
         for _ in 0..workers {
-            let _ = empty_buf_tx.send(Buf(0, Vec::with_capacity(BUF_SIZE)));
-            let _ = empty_buf_tx.send(Buf(0, Vec::with_capacity(BUF_SIZE)));
-            let empty_buf_tx = empty_buf_tx.clone();
             let full_buf_rx = full_buf_rx.clone();
             let results_tx = results_tx.clone();
 
             s.spawn(move |_| {
-                for mut buf in full_buf_rx {
-                    let mut hasher = HashWriter::new(SipHasher::new());
-                    hasher.hash.write_u64(buf.0);
-                    hasher.write_all(&buf.1[..]).expect("hash should not fail");
+                for buf in full_buf_rx {
+                    let mut hasher = HashWriter::new(BlockHasher::new_leaf(algorithm, buf.0));
+                    hasher
+                        .write_all(buf.1.as_slice())
+                        .expect("hash should not fail");
                     if results_tx
                         .send(HashResultIdx(buf.0, hasher.close()))
                         .is_err()
@@ -108,62 +396,190 @@ fn main() {
                         break;
                     }
 
-                    buf.1.clear();
-                    let _ = empty_buf_tx.send(buf);
+                    if let BufData::Owned(mut vec) = buf.1 {
+                        vec.clear();
+                        if let Some(pool) = pool {
+                            pool.release(vec);
+                        }
+                    }
                 }
             });
         }
-        drop(empty_buf_tx);
         drop(full_buf_rx);
         drop(results_tx);
 
-        s.spawn(|_| {
+        s.spawn(move |_| {
             let mut results = BinaryHeap::new();
             let mut next = 0;
 
-            let mut total = HashResult::default();
+            let mut tree = MerkleTree::new(algorithm);
+            let mut len = 0;
 
             for result in results_rx {
                 results.push(result);
 
                 while results.peek().map(|x| x.0) == Some(next) {
-                    let HashResultIdx(_, hash) = results.pop().expect("binary heap pop");
+                    let HashResultIdx(index, hash) = results.pop().expect("binary heap pop");
                     next += 1;
 
-                    total.add(hash);
+                    if let Some(observer) = observer {
+                        let offset = index * BUF_SIZE as u64;
+                        observer(index, offset, hash.len, &hash.hash);
+                    }
+
+                    len += hash.len;
+                    tree.push_leaf(hash.hash);
                 }
             }
 
-            let _ = finish_tx.send(total);
+            let _ = finish_tx.send(HashResult {
+                hash: tree.finish(),
+                len,
+            });
         });
 
-        let stdin = std::io::stdin();
-        let mut input = stdin.lock();
-        let mut block = 0;
-        for mut buf in empty_buf_rx {
-            match input.by_ref().take(BUF_SIZE as u64).read_to_end(&mut buf.1) {
-                Ok(0) => {
-                    break;
-                }
-                Ok(_) => {
-                    buf.0 = block;
-                    full_buf_tx.send(buf).expect("worker thread must live");
-                    block += 1;
-                }
-                Err(e) => {
-                    eprintln!("{}", e);
-                    exitcode = 1;
-                    break;
-                }
-            }
-        }
+        let exitcode = feed(&full_buf_tx);
         drop(full_buf_tx);
+        exitcode
     })
     .expect("thread");
 
     let result = finish_rx.recv().expect("result");
-    println!("SIP128/{} = {:x}", BUF_SIZE, result.hash);
-    println!("LEN = {}", result.len);
+    (result, exitcode)
+}
+
+fn feed_stream(
+    mut input: impl Read,
+    pool: &BufferPool,
+    tx: &crossbeam_channel::Sender<Buf>,
+) -> i32 {
+    let mut block = 0;
+    loop {
+        let mut data = pool.acquire();
+        match input.by_ref().take(BUF_SIZE as u64).read_to_end(&mut data) {
+            Ok(0) => {
+                pool.release(data);
+                return 0;
+            }
+            Ok(_) => {
+                tx.send(Buf(block, BufData::Owned(data)))
+                    .expect("worker thread must live");
+                block += 1;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                pool.release(data);
+                return 1;
+            }
+        }
+    }
+}
+
+fn feed_mmap<'env>(data: &'env [u8], tx: &crossbeam_channel::Sender<Buf<'env>>) -> i32 {
+    for (index, chunk) in data.chunks(BUF_SIZE).enumerate() {
+        tx.send(Buf(index as u64, BufData::Borrowed(chunk)))
+            .expect("worker thread must live");
+    }
+    0
+}
+
+fn try_mmap(file: &std::fs::File) -> Option<memmap2::Mmap> {
+    let meta = file.metadata().ok()?;
+    if !meta.is_file() || meta.len() == 0 {
+        return None;
+    }
+    unsafe { memmap2::Mmap::map(file) }.ok()
+}
+
+fn hash_path(
+    path: &str,
+    workers: usize,
+    algorithm: Algorithm,
+    observer: Option<&BlockObserver>,
+    pool: &BufferPool,
+) -> (HashResult, i32) {
+    if path == "-" {
+        return hash_pipeline(workers, algorithm, observer, Some(pool), |tx| {
+            feed_stream(std::io::stdin().lock(), pool, tx)
+        });
+    }
+
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return (HashResult::default(), 1);
+        }
+    };
+
+    match try_mmap(&file) {
+        Some(mmap) => {
+            let data: &[u8] = &mmap[..];
+            hash_pipeline(workers, algorithm, observer, None, move |tx| {
+                feed_mmap(data, tx)
+            })
+        }
+        None => hash_pipeline(workers, algorithm, observer, Some(pool), |tx| {
+            feed_stream(file, pool, tx)
+        }),
+    }
+}
+
+fn manifest_observer(path: Option<&str>) -> BlockObserver {
+    match path {
+        Some(path) => {
+            let path = path.to_string();
+            Box::new(move |_index, offset, len, digest: &Digest| {
+                println!("{} {} {} {:x}", path, offset, len, digest);
+            })
+        }
+        None => Box::new(|_index, offset, len, digest: &Digest| {
+            println!("{} {} {:x}", offset, len, digest);
+        }),
+    }
+}
+
+fn main() {
+    let workers = num_cpus::get();
+    let opts = parse_options();
+    let algorithm = opts.algorithm;
+    let buf_pool = BufferPool::new(workers * 2, BUF_SIZE);
+
+    if opts.paths.is_empty() {
+        let observer: Option<BlockObserver> = opts.manifest.then(|| manifest_observer(None));
+        let (result, exitcode) =
+            hash_pipeline(workers, algorithm, observer.as_ref(), Some(&buf_pool), |tx| {
+                feed_stream(std::io::stdin().lock(), &buf_pool, tx)
+            });
+
+        println!("{}/{} = {:x}", algorithm.name(), BUF_SIZE, result.hash);
+        println!("LEN = {}", result.len);
+
+        std::process::exit(exitcode);
+    }
+
+    let mut exitcode = 0;
+    let mut rollup = MerkleTree::new(algorithm);
+    let mut total_len = 0;
+
+    for path in &opts.paths {
+        let observer: Option<BlockObserver> =
+            opts.manifest.then(|| manifest_observer(Some(path)));
+        let (result, code) = hash_path(path, workers, algorithm, observer.as_ref(), &buf_pool);
+        if code != 0 {
+            exitcode = code;
+            continue;
+        }
+
+        println!("{} = {:x} LEN = {}", path, result.hash, result.len);
+
+        total_len += result.len;
+        rollup.push_leaf(result.hash);
+    }
+
+    if opts.paths.len() > 1 {
+        println!("TOTAL = {:x} LEN = {}", rollup.finish(), total_len);
+    }
 
     std::process::exit(exitcode);
 }